@@ -0,0 +1,211 @@
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use m3u8_rs::{Key, KeyMethod};
+use reqwest::header::HeaderMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::downloader;
+use crate::utils;
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// Caches fetched AES key bytes by key URL so concurrent segments that share
+/// the same `EXT-X-KEY` fetch it once instead of once per segment; cheap to
+/// clone since it's just a handle to the shared map.
+#[derive(Default, Clone)]
+pub struct KeyCache {
+    bytes_by_url: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl KeyCache {
+    async fn get_or_fetch(
+        &self,
+        client: &reqwest::Client,
+        key_url: &Url,
+        headers: Option<HeaderMap>,
+        retries: u32,
+        retry_backoff_ms: u64,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let cache_key = key_url.as_str().to_string();
+        {
+            let cache = self.bytes_by_url.lock().await;
+            if let Some(bytes) = cache.get(&cache_key) {
+                return Ok(bytes.clone());
+            }
+        }
+
+        let key_bytes = downloader::download_file_with_retries(
+            client,
+            key_url,
+            headers,
+            retries,
+            retry_backoff_ms,
+        )
+        .await?;
+        if key_bytes.len() != 16 {
+            return Err(format!("Unexpected AES-128 key length: {}", key_bytes.len()).into());
+        }
+
+        let mut cache = self.bytes_by_url.lock().await;
+        Ok(cache.entry(cache_key).or_insert(key_bytes).clone())
+    }
+}
+
+/// Tracks the `EXT-X-KEY` currently in force while walking a media
+/// playlist's segments, since the tag applies to every segment until a new
+/// one (or `METHOD=NONE`) appears.
+#[derive(Default, Clone)]
+pub struct KeyState {
+    current: Option<Key>,
+}
+
+impl KeyState {
+    pub fn advance(&mut self, segment_key: &Option<Key>) {
+        if let Some(key) = segment_key {
+            self.current = match key.method {
+                KeyMethod::None => None,
+                _ => Some(key.clone()),
+            };
+        }
+    }
+
+    pub fn current(&self) -> Option<&Key> {
+        self.current.as_ref()
+    }
+}
+
+/// Given the ciphertext downloaded for a segment and the `EXT-X-KEY` in
+/// force for it, either decrypts it in place or, if `decrypt_in_place` is
+/// false, leaves it untouched and saves the fetched key alongside the
+/// segment instead. Keys other than `METHOD=AES-128` (including `NONE`)
+/// pass the content through unchanged.
+#[allow(clippy::too_many_arguments)]
+pub async fn process_segment(
+    client: &reqwest::Client,
+    base_url: &Url,
+    headers: Option<HeaderMap>,
+    retries: u32,
+    retry_backoff_ms: u64,
+    key: Option<&Key>,
+    media_sequence: u64,
+    content: Vec<u8>,
+    decrypt_in_place: bool,
+    output_dir: &std::path::Path,
+    key_rel_path: Option<&std::path::Path>,
+    key_cache: &KeyCache,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let Some(key) = key else {
+        return Ok(content);
+    };
+    if !matches!(key.method, KeyMethod::AES128) {
+        return Ok(content);
+    }
+
+    let key_uri = key.uri.as_ref().ok_or("EXT-X-KEY is missing a URI")?;
+    let key_url = base_url.join(key_uri)?;
+    let key_bytes = key_cache
+        .get_or_fetch(client, &key_url, headers, retries, retry_backoff_ms)
+        .await?;
+
+    if !decrypt_in_place {
+        // save under the same relative path the rewritten playlist's
+        // `key.uri` points at, so the two always agree; fall back to the
+        // key URL's basename if that path couldn't be resolved
+        let key_save_path = match key_rel_path {
+            Some(rel) => output_dir.join(rel),
+            None => {
+                let key_file_name =
+                    utils::get_filename_from_url(&key_url).unwrap_or_else(|| "key.bin".to_string());
+                output_dir.join(key_file_name)
+            }
+        };
+        utils::save_file(&key_bytes, &key_save_path)?;
+        return Ok(content);
+    }
+
+    let iv = resolve_iv(key, media_sequence)?;
+    let mut buf = content;
+    let decrypted_len = Aes128CbcDec::new(key_bytes.as_slice().into(), iv.as_slice().into())
+        .decrypt_padded_mut::<Pkcs7>(&mut buf)
+        .map_err(|e| format!("Failed to decrypt segment: {:?}", e))?
+        .len();
+    buf.truncate(decrypted_len);
+    Ok(buf)
+}
+
+fn resolve_iv(key: &Key, media_sequence: u64) -> Result<[u8; 16], Box<dyn std::error::Error>> {
+    match &key.iv {
+        Some(iv) => {
+            let hex_str = iv.trim_start_matches("0x").trim_start_matches("0X");
+            let bytes = hex::decode(hex_str)?;
+            if bytes.len() != 16 {
+                return Err(format!("Unexpected IV length: {}", bytes.len()).into());
+            }
+            let mut iv_bytes = [0u8; 16];
+            iv_bytes.copy_from_slice(&bytes);
+            Ok(iv_bytes)
+        }
+        None => {
+            let mut iv_bytes = [0u8; 16];
+            iv_bytes[8..].copy_from_slice(&media_sequence.to_be_bytes());
+            Ok(iv_bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(method: KeyMethod, iv: Option<&str>) -> Key {
+        Key {
+            method,
+            uri: Some("key.bin".to_string()),
+            iv: iv.map(String::from),
+            keyformat: None,
+            keyformatversions: None,
+        }
+    }
+
+    #[test]
+    fn test_resolve_iv_uses_explicit_iv_over_media_sequence() {
+        let k = key(KeyMethod::AES128, Some("0x000102030405060708090A0B0C0D0E0F"));
+        let iv = resolve_iv(&k, 42).unwrap();
+        assert_eq!(iv, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn test_resolve_iv_falls_back_to_media_sequence() {
+        let k = key(KeyMethod::AES128, None);
+        let iv = resolve_iv(&k, 42).unwrap();
+        let mut expected = [0u8; 16];
+        expected[8..].copy_from_slice(&42u64.to_be_bytes());
+        assert_eq!(iv, expected);
+    }
+
+    #[test]
+    fn test_resolve_iv_rejects_wrong_length() {
+        let k = key(KeyMethod::AES128, Some("0xaabb"));
+        assert!(resolve_iv(&k, 0).is_err());
+    }
+
+    #[test]
+    fn test_key_state_clears_on_method_none() {
+        let mut state = KeyState::default();
+        state.advance(&Some(key(KeyMethod::AES128, None)));
+        assert!(state.current().is_some());
+        state.advance(&Some(key(KeyMethod::None, None)));
+        assert!(state.current().is_none());
+    }
+
+    #[test]
+    fn test_key_state_keeps_current_when_segment_has_no_key_tag() {
+        let mut state = KeyState::default();
+        state.advance(&Some(key(KeyMethod::AES128, None)));
+        state.advance(&None);
+        assert!(state.current().is_some());
+    }
+}
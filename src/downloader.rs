@@ -1,25 +1,85 @@
 use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::time::Duration;
 use url::Url;
 
-// todo: reuse the client
-pub async fn download_file(client: &reqwest::Client, url: &Url, headers: Option<HeaderMap>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    println!("[debug] Downloading file: {}", url);
+/// Default number of attempts before giving up on a single file.
+pub const DEFAULT_RETRIES: u32 = 5;
+/// Base backoff between attempts; doubles after every retry.
+pub const DEFAULT_RETRY_BACKOFF_MS: u64 = 500;
 
-    let request = client.get(url.as_str());
+/// Downloads `url`, retrying transient failures (connection errors,
+/// timeouts, 5xx/429 responses) with exponential backoff, honoring a
+/// `Retry-After` header when the server sends one.
+pub async fn download_file_with_retries(
+    client: &reqwest::Client,
+    url: &Url,
+    headers: Option<HeaderMap>,
+    max_attempts: u32,
+    backoff_ms: u64,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let max_attempts = max_attempts.max(1);
+    let mut attempt = 0;
+    let mut backoff = Duration::from_millis(backoff_ms);
 
-    let request = if let Some(hdrs) = headers {
-        request.headers(hdrs)
-    } else {
-        request
-    };
+    loop {
+        attempt += 1;
 
-    let response = request.send().await?;
+        let request = client.get(url.as_str());
+        let request = if let Some(hdrs) = headers.clone() {
+            request.headers(hdrs)
+        } else {
+            request
+        };
 
-    if response.status().is_success() {
-        let response_headers = response.headers();
-        for (name, value) in response_headers {
-            println!("[debug] Header: {:?} = {:?}", name, value);
+        let result = request.send().await;
+
+        let retryable = match &result {
+            Ok(response) => {
+                let status = response.status();
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+        };
+
+        if !retryable {
+            return match result {
+                Ok(response) => finish_response(response).await,
+                Err(e) => Err(e.into()),
+            };
+        }
+
+        if attempt >= max_attempts {
+            return match result {
+                Ok(response) => Err(format!(
+                    "Failed to download file after {} attempts: {}",
+                    attempt,
+                    response.status()
+                )
+                .into()),
+                Err(e) => Err(e.into()),
+            };
         }
+
+        let retry_after = match &result {
+            Ok(response) => response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs),
+            Err(_) => None,
+        };
+        let wait = retry_after.unwrap_or(backoff);
+        tokio::time::sleep(wait).await;
+        backoff *= 2;
+    }
+}
+
+async fn finish_response(
+    response: reqwest::Response,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if response.status().is_success() {
         let content = response.bytes().await?.to_vec();
         Ok(content)
     } else {
@@ -27,3 +87,130 @@ pub async fn download_file(client: &reqwest::Client, url: &Url, headers: Option<
         Err(format!("Failed to download file: {}", response.status()).into())
     }
 }
+
+/// Builds a redirect policy that stops following after `max_redirects` hops
+/// and treats a redirect into an obvious 404 path as a terminal stop rather
+/// than an error, so a broken CDN redirect chain doesn't spin forever.
+pub fn build_redirect_policy(max_redirects: usize) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            attempt.error("too many redirects")
+        } else if attempt.url().path().contains("404") {
+            attempt.stop()
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use warp::http::StatusCode as WarpStatusCode;
+    use warp::Filter;
+
+    /// Spawns `route` on an ephemeral loopback port and returns its base URL.
+    async fn spawn_server(
+        route: impl warp::Filter<Extract = impl warp::Reply> + Clone + Send + Sync + 'static,
+    ) -> Url {
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        Url::parse(&format!("http://{}/", addr)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures_then_succeeds() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let attempts_filter = attempts.clone();
+        let route = warp::path::end().map(move || {
+            if attempts_filter.fetch_add(1, Ordering::SeqCst) < 2 {
+                warp::reply::with_status("retry me", WarpStatusCode::SERVICE_UNAVAILABLE)
+            } else {
+                warp::reply::with_status("ok", WarpStatusCode::OK)
+            }
+        });
+        let url = spawn_server(route).await;
+
+        let client = reqwest::Client::new();
+        let content = download_file_with_retries(&client, &url, None, 5, 1)
+            .await
+            .unwrap();
+
+        assert_eq!(content, b"ok");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let route =
+            warp::path::end().map(|| warp::reply::with_status("down", WarpStatusCode::SERVICE_UNAVAILABLE));
+        let url = spawn_server(route).await;
+
+        let client = reqwest::Client::new();
+        let result = download_file_with_retries(&client, &url, None, 3, 1).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_honors_retry_after_header_over_configured_backoff() {
+        let route = warp::path::end().map(move || {
+            warp::reply::with_header(
+                warp::reply::with_status("slow down", WarpStatusCode::TOO_MANY_REQUESTS),
+                "Retry-After",
+                "0",
+            )
+        });
+        let url = spawn_server(route).await;
+
+        let client = reqwest::Client::new();
+        let start = std::time::Instant::now();
+        // backoff_ms is huge; a passing test proves Retry-After: 0 won out
+        let result = download_file_with_retries(&client, &url, None, 2, 60_000).await;
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_redirect_policy_stops_at_404_path_instead_of_erroring() {
+        let route = warp::path::end().map(|| {
+            warp::reply::with_header(
+                warp::reply::with_status("", WarpStatusCode::FOUND),
+                "Location",
+                "/missing-404",
+            )
+        });
+        let url = spawn_server(route).await;
+
+        let client = reqwest::Client::builder()
+            .redirect(build_redirect_policy(10))
+            .build()
+            .unwrap();
+        let response = client.get(url.as_str()).send().await.unwrap();
+
+        assert!(response.status().is_redirection());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_policy_errors_past_max_redirects() {
+        let route = warp::path::end().map(|| {
+            warp::reply::with_header(
+                warp::reply::with_status("", WarpStatusCode::FOUND),
+                "Location",
+                "/",
+            )
+        });
+        let url = spawn_server(route).await;
+
+        let client = reqwest::Client::builder()
+            .redirect(build_redirect_policy(2))
+            .build()
+            .unwrap();
+        let result = client.get(url.as_str()).send().await;
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// An HLS manifest URL recovered from a page URL, plus any HTTP headers the
+/// extractor says are required to fetch it (referer, cookies, auth, ...).
+pub struct ExtractedSource {
+    pub url: String,
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct YtDlpFormat {
+    url: String,
+    protocol: Option<String>,
+    http_headers: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct YtDlpInfo {
+    url: Option<String>,
+    protocol: Option<String>,
+    http_headers: Option<HashMap<String, String>>,
+    formats: Option<Vec<YtDlpFormat>>,
+}
+
+fn is_hls(protocol: &Option<String>) -> bool {
+    protocol
+        .as_deref()
+        .map(|p| p.contains("m3u8"))
+        .unwrap_or(false)
+}
+
+/// Shells out to `binary` (a yt-dlp/youtube-dl compatible extractor) to
+/// recover the HLS manifest URL behind a regular webpage URL.
+pub async fn extract(
+    binary: &str,
+    page_url: &str,
+) -> Result<ExtractedSource, Box<dyn std::error::Error>> {
+    let output = Command::new(binary)
+        .arg("--dump-single-json")
+        .arg(page_url)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run extractor binary `{}`: {}", binary, e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "Extractor `{}` exited with {}: {}",
+            binary,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    parse_extracted(&output.stdout)
+}
+
+/// Picks the HLS manifest URL (and its required headers) out of a yt-dlp
+/// `--dump-single-json` payload: the top-level info if it's already HLS,
+/// otherwise the first HLS entry in `formats`.
+fn parse_extracted(json: &[u8]) -> Result<ExtractedSource, Box<dyn std::error::Error>> {
+    let info: YtDlpInfo =
+        serde_json::from_slice(json).map_err(|e| format!("Failed to parse extractor output: {}", e))?;
+
+    if is_hls(&info.protocol) {
+        if let Some(url) = info.url {
+            return Ok(ExtractedSource {
+                url,
+                headers: info.http_headers.unwrap_or_default(),
+            });
+        }
+    }
+
+    let format = info
+        .formats
+        .unwrap_or_default()
+        .into_iter()
+        .find(|f| is_hls(&f.protocol))
+        .ok_or("Extractor did not return an HLS format for this URL")?;
+
+    Ok(ExtractedSource {
+        url: format.url,
+        headers: format.http_headers.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_extracted_uses_top_level_hls_info() {
+        let json = br#"{
+            "url": "https://example.com/master.m3u8",
+            "protocol": "m3u8_native",
+            "http_headers": {"Referer": "https://example.com/"}
+        }"#;
+        let extracted = parse_extracted(json).unwrap();
+        assert_eq!(extracted.url, "https://example.com/master.m3u8");
+        assert_eq!(
+            extracted.headers.get("Referer").map(String::as_str),
+            Some("https://example.com/")
+        );
+    }
+
+    #[test]
+    fn test_parse_extracted_falls_back_to_first_hls_format() {
+        let json = br#"{
+            "formats": [
+                {"url": "https://example.com/video.mp4", "protocol": "https"},
+                {"url": "https://example.com/stream.m3u8", "protocol": "m3u8"}
+            ]
+        }"#;
+        let extracted = parse_extracted(json).unwrap();
+        assert_eq!(extracted.url, "https://example.com/stream.m3u8");
+    }
+
+    #[test]
+    fn test_parse_extracted_errors_when_no_hls_format_present() {
+        let json = br#"{
+            "formats": [
+                {"url": "https://example.com/video.mp4", "protocol": "https"}
+            ]
+        }"#;
+        assert!(parse_extracted(json).is_err());
+    }
+}
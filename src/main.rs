@@ -1,20 +1,23 @@
+mod decrypt;
 mod downloader;
+mod extractor;
 mod server;
 mod utils;
 
-use futures::stream::{FuturesOrdered, StreamExt};
-use m3u8_rs;
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
 use m3u8_rs::MasterPlaylist;
 use m3u8_rs::Playlist;
-use reqwest;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::pin::Pin;
 use std::str::FromStr;
-use tokio;
-use toml;
+use std::time::Instant;
 use url::Url;
 
+type SegmentFuture = Pin<Box<dyn std::future::Future<Output = Result<u64, Box<dyn std::error::Error>>> + Send>>;
+
 #[derive(Deserialize)]
 struct Args {
     /// The URL of the m3u8 playlist
@@ -29,6 +32,39 @@ struct Args {
     count: Option<usize>,
     /// Server port to use
     port: Option<u16>,
+    /// Max number of attempts per file before giving up (default 5)
+    retries: Option<u32>,
+    /// Base backoff in milliseconds between retries, doubling each attempt
+    retry_backoff_ms: Option<u64>,
+    /// Per-request timeout in seconds
+    timeout: Option<u64>,
+    /// Keep polling the media playlist for new segments instead of stopping
+    /// after the first fetch; only meaningful while the playlist lacks
+    /// EXT-X-ENDLIST
+    live: Option<bool>,
+    /// Seconds between playlist refreshes in live mode (default: the
+    /// playlist's own target duration)
+    poll_interval: Option<f32>,
+    /// Desired vertical resolution of a master playlist variant, e.g. "1080p"
+    resolution: Option<String>,
+    /// Drop variants whose bandwidth exceeds this many bits per second
+    max_bandwidth: Option<u64>,
+    /// "best" or "worst" by bandwidth; combined with `resolution`/`max_bandwidth`
+    /// as a tie-breaker. Downloads every variant only when `variant`,
+    /// `resolution` and `max_bandwidth` are all unset; otherwise a single
+    /// variant is picked (the highest bandwidth when `variant` is unset).
+    variant: Option<String>,
+    /// Decrypt AES-128 segments in place (default true). When false, the
+    /// key is saved next to the segment and the segment is left encrypted.
+    decrypt_segments: Option<bool>,
+    /// Force running `url` through an extractor (currently only "yt-dlp")
+    /// instead of treating it as a raw playlist URL. Detected automatically
+    /// when `url` doesn't look like an `.m3u8` link.
+    extractor: Option<String>,
+    /// Path to the yt-dlp/youtube-dl binary (default "yt-dlp")
+    yt_dlp_binary: Option<String>,
+    /// Max number of segments downloading at once (default 8)
+    concurrency: Option<usize>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -51,6 +87,15 @@ struct StreamConfig {
     output_dir: std::path::PathBuf,
     length: FetchLength,
     port: u16,
+    retries: u32,
+    retry_backoff_ms: u64,
+    live: bool,
+    poll_interval: Option<f32>,
+    resolution: Option<String>,
+    max_bandwidth: Option<u64>,
+    variant: Option<String>,
+    decrypt_segments: bool,
+    concurrency: usize,
 }
 
 fn parse_headers(headers: &HashMap<String, HeadersValue>) -> Option<HeaderMap> {
@@ -76,6 +121,48 @@ fn parse_headers(headers: &HashMap<String, HeadersValue>) -> Option<HeaderMap> {
     Some(header_map)
 }
 
+/// Writes out the accumulated segment list under `first_sequence`, so the
+/// saved playlist grows across live refreshes instead of being overwritten
+/// with whatever short sliding window the last fetch happened to return.
+fn save_media_playlist(
+    media_file_path: &std::path::Path,
+    template: &m3u8_rs::MediaPlaylist,
+    segments: Vec<m3u8_rs::MediaSegment>,
+    first_sequence: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rewritten = template.clone();
+    rewritten.media_sequence = first_sequence;
+    rewritten.segments = segments;
+
+    let mut bytes = Vec::new();
+    rewritten.write_to(&mut bytes)?;
+    utils::save_file(&bytes, media_file_path)?;
+    println!("Media playlist saved to: {}", media_file_path.display());
+    Ok(())
+}
+
+/// Decides which of a freshly fetched manifest window's segments are new,
+/// given the highest absolute `EXT-X-MEDIA-SEQUENCE` already fetched in a
+/// prior refresh. Returns the index to start iterating `manifest.segments`
+/// from (clamped to `segment_count`, so an entirely-stale window yields no
+/// new segments) and how many segments scrolled out of the window before
+/// they could be captured, so the caller can warn about the gap.
+fn new_segment_range(
+    media_sequence: u64,
+    segment_count: usize,
+    highest_fetched_sequence: Option<u64>,
+) -> (usize, u64) {
+    match highest_fetched_sequence {
+        None => (0, 0),
+        Some(highest) if highest + 1 < media_sequence => (0, media_sequence - highest - 1),
+        Some(highest) if highest >= media_sequence => {
+            let start = (highest - media_sequence + 1) as usize;
+            (start.min(segment_count), 0)
+        }
+        Some(_) => (0, 0),
+    }
+}
+
 async fn handle_media_manifest(
     manifest_url: &Url,
     base_url: &Url,
@@ -87,94 +174,332 @@ async fn handle_media_manifest(
     let mut path = utils::get_relative_path(base_url, manifest_url)?;
     let media_file_path = config.output_dir.join(&path);
 
-    let content =
-        downloader::download_file(&config.client, manifest_url, config.headers.clone()).await?;
-    utils::save_file(&content, &media_file_path)?;
-    println!("Media playlist saved to: {}", media_file_path.display());
-
-    let manifest = match m3u8_rs::parse_playlist(&content) {
-        Ok((_, Playlist::MediaPlaylist(pl))) => pl,
-        Ok((_, Playlist::MasterPlaylist(_))) => {
-            return Err("Trying to process master playlist as media list".into())
-        }
-        Err(_) => return Err("Not a media playlist".into()),
-    };
-
     let mut output_dir = config.output_dir.clone();
     if path.pop() {
         output_dir = output_dir.join(&path);
     }
-    let mut segment_count = manifest.segments.len();
-    match config.length {
-        FetchLength::Duration(duration) => {
-            let mut dur_sum = 0.0;
-            for (i, segment) in manifest.segments.iter().enumerate() {
-                dur_sum += segment.duration;
-                if dur_sum >= duration {
-                    segment_count = i;
-                    println!("Duration limit reached at segment: {}", i);
+    let segment_base_url = utils::get_base_url(manifest_url);
+
+    let mut accumulated_segments: Vec<m3u8_rs::MediaSegment> = Vec::new();
+    let mut first_sequence: Option<u64> = None;
+    let mut highest_fetched_sequence: Option<u64> = None;
+    let mut duration_sum = 0.0f32;
+    let mut key_state = decrypt::KeyState::default();
+    let key_cache = decrypt::KeyCache::default();
+    let mut total_bytes: u64 = 0;
+    let mut total_downloaded: u64 = 0;
+    let start_time = Instant::now();
+
+    loop {
+        let content = downloader::download_file_with_retries(
+            &config.client,
+            manifest_url,
+            config.headers.clone(),
+            config.retries,
+            config.retry_backoff_ms,
+        )
+        .await?;
+
+        let manifest = match m3u8_rs::parse_playlist(&content) {
+            Ok((_, Playlist::MediaPlaylist(pl))) => pl,
+            Ok((_, Playlist::MasterPlaylist(_))) => {
+                return Err("Trying to process master playlist as media list".into())
+            }
+            Err(_) => return Err("Not a media playlist".into()),
+        };
+
+        first_sequence.get_or_insert(manifest.media_sequence);
+
+        let (start_index, scrolled_out) =
+            new_segment_range(manifest.media_sequence, manifest.segments.len(), highest_fetched_sequence);
+        if scrolled_out > 0 {
+            println!(
+                "[warn] playlist window advanced past sequence {}, {} segment(s) scrolled out before they were captured",
+                highest_fetched_sequence.unwrap(),
+                scrolled_out
+            );
+        }
+
+        let mut segment_futures: Vec<SegmentFuture> = Vec::new();
+        let mut limit_reached = false;
+
+        for (i, segment) in manifest.segments.iter().enumerate().skip(start_index) {
+            let absolute_sequence = manifest.media_sequence + i as u64;
+
+            match config.length {
+                FetchLength::Duration(limit) if duration_sum >= limit => {
+                    limit_reached = true;
+                    break;
+                }
+                FetchLength::Count(limit) if accumulated_segments.len() >= limit => {
+                    limit_reached = true;
                     break;
                 }
+                _ => {}
+            }
+
+            key_state.advance(&segment.key);
+            let segment_key = key_state.current().cloned();
+
+            let (segment_uri, segment_rel_path) =
+                utils::resolve_relative(&segment_base_url, &segment.uri)?;
+            let segment_file_path = output_dir.join(&segment_rel_path);
+
+            let mut rewritten_segment = segment.clone();
+            rewritten_segment.uri = segment_rel_path.to_string_lossy().into_owned();
+
+            // derive the key's relative path from `segment_key` (the
+            // key_state-carried-forward value), not the raw per-segment
+            // `segment.key` field: m3u8_rs only populates that field on the
+            // one segment immediately following EXT-X-KEY, so every later
+            // segment still covered by the same key would otherwise lose
+            // track of where its key was saved
+            let mut key_rel_path: Option<std::path::PathBuf> = None;
+            if !config.decrypt_segments {
+                if let Some(key_uri) = segment_key.as_ref().and_then(|k| k.uri.as_ref()) {
+                    if let Ok((_, rel_path)) = utils::resolve_relative(&segment_base_url, key_uri) {
+                        key_rel_path = Some(rel_path);
+                    }
+                }
             }
-        },
-        FetchLength::Count(count) => {
-            segment_count = std::cmp::min(segment_count, count);
+            if let Some(mut key) = rewritten_segment.key.clone() {
+                if config.decrypt_segments {
+                    // the saved segment is plaintext now; a key tag would
+                    // make players try to decrypt it a second time
+                    rewritten_segment.key = None;
+                } else {
+                    if let Some(rel_path) = &key_rel_path {
+                        key.uri = Some(rel_path.to_string_lossy().into_owned());
+                    }
+                    rewritten_segment.key = Some(key);
+                }
+            }
+            if let Some(mut map) = rewritten_segment.map.clone() {
+                if let Ok((map_url, map_rel_path)) =
+                    utils::resolve_relative(&segment_base_url, &map.uri)
+                {
+                    let map_file_path = output_dir.join(&map_rel_path);
+                    if !map_file_path.exists() {
+                        let map_content = downloader::download_file_with_retries(
+                            &config.client,
+                            &map_url,
+                            config.headers.clone(),
+                            config.retries,
+                            config.retry_backoff_ms,
+                        )
+                        .await?;
+                        utils::save_file(&map_content, &map_file_path)?;
+                    }
+                    map.uri = map_rel_path.to_string_lossy().into_owned();
+                }
+                rewritten_segment.map = Some(map);
+            }
+
+            highest_fetched_sequence = Some(absolute_sequence);
+            duration_sum += segment.duration;
+            accumulated_segments.push(rewritten_segment);
+
+            if segment_file_path.exists() {
+                println!(
+                    "[seq {}] Segment already exists: {}",
+                    absolute_sequence, segment_file_path.display()
+                );
+                continue;
+            }
+            let key_base_url = segment_base_url.clone();
+            let output_dir = output_dir.clone();
+            // SegmentFuture is a 'static boxed future, so it can't borrow
+            // `config`; clone the handful of fields the task needs instead
+            let client = config.client.clone();
+            let headers = config.headers.clone();
+            let retries = config.retries;
+            let retry_backoff_ms = config.retry_backoff_ms;
+            let decrypt_segments = config.decrypt_segments;
+            let key_cache = key_cache.clone();
+
+            segment_futures.push(Box::pin(async move {
+                let segment_content = downloader::download_file_with_retries(
+                    &client,
+                    &segment_uri,
+                    headers.clone(),
+                    retries,
+                    retry_backoff_ms,
+                )
+                .await?;
+                let segment_content = decrypt::process_segment(
+                    &client,
+                    &key_base_url,
+                    headers,
+                    retries,
+                    retry_backoff_ms,
+                    segment_key.as_ref(),
+                    absolute_sequence,
+                    segment_content,
+                    decrypt_segments,
+                    &output_dir,
+                    key_rel_path.as_deref(),
+                    &key_cache,
+                )
+                .await?;
+                let byte_count = segment_content.len() as u64;
+                utils::save_file(&segment_content, &segment_file_path)?;
+
+                Ok::<u64, Box<dyn std::error::Error>>(byte_count)
+            }));
+        }
+
+        let batch_size = segment_futures.len();
+        if batch_size > 0 {
+            let progress = ProgressBar::new(batch_size as u64);
+            progress.set_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise}] [{bar:40}] {pos}/{len} segments ({msg})",
+                )
+                .unwrap_or_else(|_| ProgressStyle::default_bar()),
+            );
+
+            let mut downloads = stream::iter(segment_futures).buffer_unordered(config.concurrency);
+            while let Some(result) = downloads.next().await {
+                let byte_count = result?;
+                total_bytes += byte_count;
+                total_downloaded += 1;
+                progress.inc(1);
+                progress.set_message(utils::format_bytes(total_bytes));
+            }
+            progress.finish_and_clear();
         }
-    }
 
-    let mut segment_tasks = FuturesOrdered::new();
-    let base_url = utils::get_base_url(&manifest_url);
+        save_media_playlist(
+            &media_file_path,
+            &manifest,
+            accumulated_segments.clone(),
+            first_sequence.unwrap(),
+        )?;
 
-    for (i, segment) in manifest.segments.iter().enumerate() {
-        if i >= segment_count {
+        if manifest.end_list {
+            println!("Reached EXT-X-ENDLIST, stopping");
+            break;
+        }
+        if limit_reached {
+            println!("Requested segment limit reached, stopping");
+            break;
+        }
+        if !config.live {
             break;
         }
-        let segment_uri = base_url.join(&segment.uri)?;
-        let segment_file_path = output_dir.join(&segment.uri);
-        if segment_file_path.exists() {
+
+        let poll_interval = config
+            .poll_interval
+            .unwrap_or_else(|| manifest.target_duration.max(1) as f32);
+        println!(
+            "Live playlist still open, polling again in {:.1}s",
+            poll_interval
+        );
+        tokio::time::sleep(std::time::Duration::from_secs_f32(poll_interval)).await;
+    }
+
+    let elapsed = start_time.elapsed();
+    let throughput = if elapsed.as_secs_f64() > 0.0 {
+        total_bytes as f64 / elapsed.as_secs_f64()
+    } else {
+        0.0
+    };
+    println!(
+        "Downloaded {} segment(s), {} in {:.1}s ({}/s average)",
+        total_downloaded,
+        utils::format_bytes(total_bytes),
+        elapsed.as_secs_f64(),
+        utils::format_bytes(throughput as u64)
+    );
+    println!("--------------------------");
+    Ok(())
+}
+
+/// Narrows `variants` down to the ones matching the user's `resolution`,
+/// `max_bandwidth` and `variant` ("best"/"worst") preferences. Returns every
+/// variant unfiltered when none of those are set, and falls back to the
+/// highest-bandwidth variant when a filter rules out everything.
+fn select_variants<'a>(
+    variants: &'a [m3u8_rs::VariantStream],
+    config: &StreamConfig,
+) -> Vec<&'a m3u8_rs::VariantStream> {
+    if config.resolution.is_none() && config.max_bandwidth.is_none() && config.variant.is_none() {
+        return variants.iter().collect();
+    }
+
+    let mut candidates: Vec<&m3u8_rs::VariantStream> = variants.iter().collect();
+
+    if let Some(resolution) = &config.resolution {
+        let target_height = resolution.trim_end_matches('p').parse::<u64>().ok();
+        let filtered: Vec<_> = candidates
+            .iter()
+            .copied()
+            .filter(|v| {
+                v.resolution
+                    .and_then(|r| target_height.map(|h| r.height == h))
+                    .unwrap_or(false)
+            })
+            .collect();
+        if filtered.is_empty() {
             println!(
-                "[{}/{}]Segment already exists: {}",
-                i + 1,
-                segment_count,
-                segment_file_path.display()
+                "[warn] no variant matched resolution {}, falling back to highest bandwidth",
+                resolution
             );
-            continue;
+            return variants.iter().max_by_key(|v| v.bandwidth).into_iter().collect();
+        } else {
+            candidates = filtered;
         }
-        let short_uri = segment.uri.clone();
+    }
 
-        segment_tasks.push_back(async move {
+    if let Some(max_bandwidth) = config.max_bandwidth {
+        let filtered: Vec<_> = candidates
+            .iter()
+            .copied()
+            .filter(|v| v.bandwidth <= max_bandwidth)
+            .collect();
+        if filtered.is_empty() {
             println!(
-                "[{}/{}]Start processing segment: {}",
-                i + 1,
-                segment_count,
-                short_uri
+                "[warn] no variant under max_bandwidth {}, falling back to highest bandwidth",
+                max_bandwidth
             );
-            let segment_content =
-                downloader::download_file(&config.client, &segment_uri, config.headers.clone())
-                    .await?;
-            utils::save_file(&segment_content, &segment_file_path)?;
-            println!("Segment saved to: {}", segment_file_path.display());
-
-            Ok::<(), Box<dyn std::error::Error>>(())
-        });
+            return variants.iter().max_by_key(|v| v.bandwidth).into_iter().collect();
+        } else {
+            candidates = filtered;
+        }
     }
 
-    while let Some(result) = segment_tasks.next().await {
-        result?;
+    if config.variant.as_deref() == Some("worst") {
+        if let Some(worst) = candidates.iter().copied().min_by_key(|v| v.bandwidth) {
+            return vec![worst];
+        }
     }
-    println!("--------------------------");
-    Ok(())
+
+    if let Some(best) = candidates.iter().copied().max_by_key(|v| v.bandwidth) {
+        return vec![best];
+    }
+
+    variants.iter().max_by_key(|v| v.bandwidth).into_iter().collect()
 }
 
 async fn handle_master_manifest(
     playlist: MasterPlaylist,
+    master_file_path: &std::path::Path,
     config: &StreamConfig,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let base_url = utils::get_base_url(&config.url);
 
-    let variant_count = playlist.variants.len();
-    println!("Processing {} variants", variant_count);
-    for (i, variant) in playlist.variants.iter().enumerate() {
+    let selected_variants = select_variants(&playlist.variants, config);
+    let variant_count = selected_variants.len();
+    println!(
+        "Processing {} of {} variant(s)",
+        variant_count,
+        playlist.variants.len()
+    );
+    let selected_uris: std::collections::HashSet<&str> =
+        selected_variants.iter().map(|v| v.uri.as_str()).collect();
+    let mut fetched_alternative_uris: std::collections::HashSet<String> =
+        std::collections::HashSet::new();
+    for (i, variant) in selected_variants.iter().enumerate() {
         println!(
             "[{}/{}] Processing variant: {}",
             i + 1,
@@ -182,9 +507,67 @@ async fn handle_master_manifest(
             variant.uri
         );
         let variant_url = base_url.join(&variant.uri)?;
-        handle_media_manifest(&variant_url, &base_url, &config).await?;
-        println!("");
+        handle_media_manifest(&variant_url, &base_url, config).await?;
+
+        for group_id in [&variant.audio, &variant.video, &variant.subtitles]
+            .into_iter()
+            .flatten()
+        {
+            for alternative in playlist
+                .alternatives
+                .iter()
+                .filter(|alt| &alt.group_id == group_id)
+            {
+                let Some(uri) = &alternative.uri else {
+                    continue;
+                };
+                if fetched_alternative_uris.contains(uri) {
+                    // already fetched for an earlier variant that shares
+                    // this group; avoid downloading it again
+                    continue;
+                }
+                println!(
+                    "Processing alternate rendition: {} ({})",
+                    alternative.name, uri
+                );
+                let alternative_url = base_url.join(uri)?;
+                handle_media_manifest(&alternative_url, &base_url, config).await?;
+                fetched_alternative_uris.insert(uri.clone());
+            }
+        }
+        println!();
+    }
+
+    // re-save the master playlist with every variant/alternate URI pointing
+    // at the relative path it was actually written to, so the tree plays
+    // back offline from wherever it's served; variants/alternates that
+    // select_variants filtered out were never downloaded, so drop them
+    // instead of pointing at a local path that was never written
+    let mut rewritten = playlist.clone();
+    rewritten
+        .variants
+        .retain(|v| selected_uris.contains(v.uri.as_str()));
+    for variant in rewritten.variants.iter_mut() {
+        if let Ok((_, rel_path)) = utils::resolve_relative(&base_url, &variant.uri) {
+            variant.uri = rel_path.to_string_lossy().into_owned();
+        }
+    }
+    rewritten.alternatives.retain(|alt| {
+        alt.uri
+            .as_deref()
+            .is_some_and(|uri| fetched_alternative_uris.contains(uri))
+    });
+    for alternative in rewritten.alternatives.iter_mut() {
+        if let Some(uri) = alternative.uri.clone() {
+            if let Ok((_, rel_path)) = utils::resolve_relative(&base_url, &uri) {
+                alternative.uri = Some(rel_path.to_string_lossy().into_owned());
+            }
+        }
     }
+    let mut bytes = Vec::new();
+    rewritten.write_to(&mut bytes)?;
+    utils::save_file(&bytes, master_file_path)?;
+    println!("Master playlist saved to: {}", master_file_path.display());
 
     Ok(())
 }
@@ -198,12 +581,61 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let output_dir = std::path::absolute(&args.output)?;
 
-    let client = reqwest::Client::new();
+    let retries = args.retries.unwrap_or(downloader::DEFAULT_RETRIES);
+    let retry_backoff_ms = args
+        .retry_backoff_ms
+        .unwrap_or(downloader::DEFAULT_RETRY_BACKOFF_MS);
+    let timeout = std::time::Duration::from_secs(args.timeout.unwrap_or(30));
+
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(downloader::build_redirect_policy(10))
+        .build()?;
     let port = args.port.unwrap_or(3030);
-    let headers = match &args.headers {
-        Some(headers) => parse_headers(headers),
-        None => None,
+
+    // suffix-match the URL's path rather than the raw string so a signed
+    // CDN playlist URL like `.../index.m3u8?token=...` is still recognized
+    let looks_like_playlist = Url::parse(&args.url)
+        .map(|u| u.path().ends_with(".m3u8") || u.path().ends_with(".m3u"))
+        .unwrap_or_else(|_| args.url.ends_with(".m3u8") || args.url.ends_with(".m3u"));
+    let resolved_url = if args.extractor.as_deref() == Some("yt-dlp") || !looks_like_playlist {
+        let binary = args.yt_dlp_binary.as_deref().unwrap_or("yt-dlp");
+        println!("Extracting HLS source via {}", binary);
+        let extracted = extractor::extract(binary, &args.url).await?;
+        println!("Extracted HLS source: {}", extracted.url);
+        Some(extracted)
+    } else {
+        None
     };
+
+    let mut header_map = HeaderMap::new();
+    if let Some(extracted) = &resolved_url {
+        for (name, value) in &extracted.headers {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::from_str(name), HeaderValue::from_str(value))
+            {
+                header_map.insert(name, value);
+            }
+        }
+    }
+    if let Some(user_headers) = &args.headers {
+        if let Some(parsed) = parse_headers(user_headers) {
+            for (name, value) in parsed.iter() {
+                header_map.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    let headers = if header_map.is_empty() {
+        None
+    } else {
+        Some(header_map)
+    };
+
+    let url = match resolved_url {
+        Some(extracted) => extracted.url,
+        None => args.url.clone(),
+    };
+
     let length = if let Some(duration) = args.duration {
         FetchLength::Duration(duration)
     } else if let Some(count) = args.count {
@@ -215,10 +647,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let stream_config = StreamConfig {
         client,
         output_dir,
-        url: Url::parse(args.url.as_str())?,
+        url: Url::parse(url.as_str())?,
         headers,
         length,
         port,
+        retries,
+        retry_backoff_ms,
+        live: args.live.unwrap_or(false),
+        poll_interval: args.poll_interval,
+        resolution: args.resolution,
+        max_bandwidth: args.max_bandwidth,
+        variant: args.variant,
+        decrypt_segments: args.decrypt_segments.unwrap_or(true),
+        concurrency: args.concurrency.unwrap_or(8).max(1),
     };
 
     println!("-------------------------");
@@ -232,10 +673,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     utils::create_dir_if_not_exists(&stream_config.output_dir)?;
 
-    let manifest = downloader::download_file(
+    let manifest = downloader::download_file_with_retries(
         &stream_config.client,
         &stream_config.url,
         stream_config.headers.clone(),
+        stream_config.retries,
+        stream_config.retry_backoff_ms,
     )
     .await?;
 
@@ -246,10 +689,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .ok_or("Failed to get filename from URL")?;
             let master_file_path = stream_config.output_dir.join(master_file_name);
 
-            utils::save_file(&manifest, &master_file_path)?;
-            println!("Master playlist saved to: {}", master_file_path.display());
-
-            handle_master_manifest(playlist, &stream_config).await?;
+            handle_master_manifest(playlist, &master_file_path, &stream_config).await?;
         }
         Ok((_, Playlist::MediaPlaylist(_))) => {
             println!("Media playlist found");
@@ -266,3 +706,123 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use m3u8_rs::{Resolution, VariantStream};
+
+    fn test_config(resolution: Option<&str>, max_bandwidth: Option<u64>, variant: Option<&str>) -> StreamConfig {
+        StreamConfig {
+            client: reqwest::Client::new(),
+            url: Url::parse("https://example.com/master.m3u8").unwrap(),
+            headers: None,
+            output_dir: std::path::PathBuf::from("/tmp"),
+            length: FetchLength::Count(usize::MAX),
+            port: 3030,
+            retries: downloader::DEFAULT_RETRIES,
+            retry_backoff_ms: downloader::DEFAULT_RETRY_BACKOFF_MS,
+            live: false,
+            poll_interval: None,
+            resolution: resolution.map(String::from),
+            max_bandwidth,
+            variant: variant.map(String::from),
+            decrypt_segments: true,
+            concurrency: 8,
+        }
+    }
+
+    fn variant(uri: &str, bandwidth: u64, height: Option<u64>) -> VariantStream {
+        VariantStream {
+            uri: uri.to_string(),
+            bandwidth,
+            resolution: height.map(|height| Resolution { width: height * 16 / 9, height }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select_variants_no_filter_returns_all() {
+        let variants = vec![variant("low.m3u8", 500_000, Some(480)), variant("high.m3u8", 5_000_000, Some(1080))];
+        let config = test_config(None, None, None);
+        let selected = select_variants(&variants, &config);
+        assert_eq!(selected.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_select_variants_by_resolution() {
+        let variants = vec![variant("low.m3u8", 500_000, Some(480)), variant("high.m3u8", 5_000_000, Some(1080))];
+        let config = test_config(Some("1080p"), None, None);
+        let selected = select_variants(&variants, &config);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].uri, "high.m3u8");
+    }
+
+    #[tokio::test]
+    async fn test_select_variants_by_max_bandwidth() {
+        let variants = vec![variant("low.m3u8", 500_000, Some(480)), variant("high.m3u8", 5_000_000, Some(1080))];
+        let config = test_config(None, Some(1_000_000), None);
+        let selected = select_variants(&variants, &config);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].uri, "low.m3u8");
+    }
+
+    #[tokio::test]
+    async fn test_select_variants_worst() {
+        let variants = vec![variant("low.m3u8", 500_000, Some(480)), variant("high.m3u8", 5_000_000, Some(1080))];
+        let config = test_config(None, None, Some("worst"));
+        let selected = select_variants(&variants, &config);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].uri, "low.m3u8");
+    }
+
+    #[tokio::test]
+    async fn test_select_variants_falls_back_when_resolution_unmatched() {
+        let variants = vec![variant("low.m3u8", 500_000, Some(480)), variant("high.m3u8", 5_000_000, Some(1080))];
+        let config = test_config(Some("720p"), None, None);
+        let selected = select_variants(&variants, &config);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].uri, "high.m3u8");
+    }
+
+    #[test]
+    fn test_new_segment_range_first_fetch_takes_everything() {
+        let (start, scrolled_out) = new_segment_range(100, 5, None);
+        assert_eq!(start, 0);
+        assert_eq!(scrolled_out, 0);
+    }
+
+    #[test]
+    fn test_new_segment_range_skips_already_fetched_overlap() {
+        // previously fetched up to sequence 102; window now starts at 100
+        // with 5 segments (100..=104), so 100..=102 were already fetched
+        let (start, scrolled_out) = new_segment_range(100, 5, Some(102));
+        assert_eq!(start, 3);
+        assert_eq!(scrolled_out, 0);
+    }
+
+    #[test]
+    fn test_new_segment_range_contiguous_window_takes_everything() {
+        // previously fetched up to 99; window starts exactly at 100
+        let (start, scrolled_out) = new_segment_range(100, 5, Some(99));
+        assert_eq!(start, 0);
+        assert_eq!(scrolled_out, 0);
+    }
+
+    #[test]
+    fn test_new_segment_range_reports_gap_when_window_skipped_ahead() {
+        // previously fetched up to 95; window jumped to 100, so 96..=99
+        // (4 segments) scrolled out before they could be captured
+        let (start, scrolled_out) = new_segment_range(100, 5, Some(95));
+        assert_eq!(start, 0);
+        assert_eq!(scrolled_out, 4);
+    }
+
+    #[test]
+    fn test_new_segment_range_clamps_when_entire_window_already_fetched() {
+        // previously fetched up to 110; window only covers 100..=104
+        let (start, scrolled_out) = new_segment_range(100, 5, Some(110));
+        assert_eq!(start, 5);
+        assert_eq!(scrolled_out, 0);
+    }
+}
@@ -7,7 +7,7 @@ pub fn get_filename_from_url(url: &Url) -> Option<String> {
     }
     let segments = url.path_segments()?.collect::<Vec<&str>>();
     let mut name = segments[segments.len() - 1].to_string();
-    if name.starts_with('.') && name.len() > 0 {
+    if name.starts_with('.') && !name.is_empty() {
         name = format!("{}{}", segments[segments.len() - 1], name);
     }
     Some(name)
@@ -20,13 +20,14 @@ pub fn create_dir_if_not_exists(dir: &std::path::Path) -> Result<(), Box<dyn std
     Ok(())
 }
 
-pub fn save_file(content: &Vec<u8>, output_file: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
-    let dir_name = output_file.parent()
-        .expect(format!("Failed to get parent directory for: {}", output_file.display()).as_str());
+pub fn save_file(content: &[u8], output_file: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let dir_name = output_file
+        .parent()
+        .unwrap_or_else(|| panic!("Failed to get parent directory for: {}", output_file.display()));
     create_dir_if_not_exists(dir_name)?;
 
     let mut file = std::fs::File::create(output_file)?;
-    file.write_all(&content)?;
+    file.write_all(content)?;
     Ok(())
 }
 
@@ -46,17 +47,46 @@ pub fn get_relative_path(base: &Url, target: &Url) -> Result<std::path::PathBuf,
     }
 
     let mut rel_path = std::path::PathBuf::new();
-    for seg in i..target_path_segments.len() {
-        rel_path.push(target_path_segments[seg]);
+    for seg in &target_path_segments[i..] {
+        rel_path.push(seg);
     }
     Ok(rel_path)
 }
 
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
 pub fn get_base_url(url: &Url) -> Url {
     let base_url = url.clone();
     base_url.join("./").unwrap()
 }
 
+/// Resolves `uri` (relative or absolute) against `base`, then relativizes
+/// the result back against `base`. This normalizes an absolute same-host
+/// URI taken from a playlist into the relative path under `base` that the
+/// corresponding file was actually saved at, while leaving an
+/// already-relative URI unchanged.
+pub fn resolve_relative(
+    base: &Url,
+    uri: &str,
+) -> Result<(Url, std::path::PathBuf), Box<dyn std::error::Error>> {
+    let absolute = base.join(uri)?;
+    let relative = get_relative_path(base, &absolute)?;
+    Ok((absolute, relative))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;